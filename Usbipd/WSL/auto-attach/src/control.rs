@@ -0,0 +1,142 @@
+// SPDX-FileCopyrightText: 2022 Frans van Dorsselaer (original shell script)
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! A tiny line-based control/status protocol served over a Unix domain
+//! socket, so external tooling can inspect or reconfigure a running
+//! auto-attach daemon without restarting it.
+//!
+//! One command per line, one response per line (or, for `status`, one line
+//! per watched device followed by a blank line). Commands:
+//!
+//! ```text
+//! status                   list every watched device and its state
+//! add HOST:BUSID           start watching an additional device
+//! add-vid-pid HOST:VID:PID start watching an additional device by vid:pid
+//! drop SPEC                stop watching a device, spelled as `status` lists it
+//! detach PORT              force-detach a vhci_hcd port
+//! ```
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+
+use crate::{spawn_device, Device, Registry, RunConfig, VidPidSpec};
+
+/// Binds `path` as a Unix domain socket and serves the control protocol to
+/// any number of concurrent clients. Never returns on success.
+pub fn serve(path: &str, registry: Registry, config: RunConfig) -> std::io::Result<()> {
+    // A stale socket file from a previous run would otherwise make bind() fail.
+    let _ = std::fs::remove_file(path);
+
+    let listener = UnixListener::bind(path)?;
+    if config.verbose {
+        eprintln!("Listening for control connections on {}", path);
+    }
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let registry = Arc::clone(&registry);
+                thread::spawn(move || handle_client(stream, registry, config));
+            },
+            Err(e) => {
+                if config.verbose {
+                    eprintln!("Control socket accept error: {}", e);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_client(stream: UnixStream, registry: Registry, config: RunConfig) {
+    let mut writer = match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let reader = BufReader::new(stream);
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        for reply in dispatch(&line, &registry, config) {
+            if writeln!(writer, "{}", reply).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Executes one command line and returns the response lines to send back.
+fn dispatch(line: &str, registry: &Registry, config: RunConfig) -> Vec<String> {
+    let mut parts = line.trim().splitn(2, char::is_whitespace);
+    let command = parts.next().unwrap_or("");
+    let argument = parts.next().unwrap_or("").trim();
+
+    match command {
+        "status" => {
+            let mut lines: Vec<String> = registry
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(spec, entry)| {
+                    let state = entry.state.lock().unwrap();
+                    let error = if state.last_error.is_empty() { "-" } else { &state.last_error };
+                    format!("{} attached={} error={}", spec, state.is_attached, error)
+                })
+                .collect();
+            lines.sort();
+            lines.push(String::new());
+            lines
+        },
+        "add" => vec![add_device(argument.parse(), registry, config)],
+        "add-vid-pid" => vec![add_device(argument.parse::<VidPidSpec>().map(|spec| spec.0), registry, config)],
+        "drop" => vec![drop_device(argument, registry, config.verbose)],
+        "detach" => vec![detach_port(argument)],
+        "" => vec!["ERROR empty command".to_string()],
+        _ => vec![format!("ERROR unknown command '{}'", command)],
+    }
+}
+
+fn add_device(device: Result<Device, String>, registry: &Registry, config: RunConfig) -> String {
+    match device {
+        Ok(device) => {
+            let spec = device.spec();
+            if registry.lock().unwrap().contains_key(&spec) {
+                return format!("ERROR already watching {}", spec);
+            }
+            spawn_device(device, config, registry);
+            format!("OK added {}", spec)
+        },
+        Err(e) => format!("ERROR {}", e),
+    }
+}
+
+fn drop_device(spec: &str, registry: &Registry, verbose: bool) -> String {
+    match registry.lock().unwrap().remove(spec) {
+        Some(entry) => {
+            entry.stop.store(true, Ordering::Relaxed);
+            if verbose {
+                eprintln!("Dropping watched device {} via control socket", spec);
+            }
+            format!("OK dropped {}", spec)
+        },
+        None => format!("ERROR not watching {}", spec),
+    }
+}
+
+fn detach_port(port: &str) -> String {
+    match port.parse::<u32>() {
+        Ok(port) => match std::fs::write("/sys/devices/platform/vhci_hcd.0/detach", format!("{}\n", port)) {
+            Ok(()) => format!("OK detached port {}", port),
+            Err(e) => format!("ERROR failed to detach port {}: {}", port, e),
+        },
+        Err(_) => format!("ERROR invalid port '{}'", port),
+    }
+}
@@ -0,0 +1,31 @@
+// SPDX-FileCopyrightText: 2022 Frans van Dorsselaer (original shell script)
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! A tiny shell-style glob matcher (`*` and `?` only) used to match bus IDs
+//! without pulling in an external dependency for it.
+
+/// Returns `true` if `text` matches `pattern`, where `*` matches any run of
+/// characters (including none) and `?` matches exactly one character.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches_from(&pattern, &text)
+}
+
+fn matches_from(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => {
+            // Either `*` matches zero characters, or it consumes one and
+            // keeps trying against the rest of the text.
+            matches_from(&pattern[1..], text) || (!text.is_empty() && matches_from(pattern, &text[1..]))
+        },
+        Some('?') => !text.is_empty() && matches_from(&pattern[1..], &text[1..]),
+        Some(&c) => text.first() == Some(&c) && matches_from(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Returns `true` if `pattern` contains glob metacharacters.
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains('*') || pattern.contains('?')
+}
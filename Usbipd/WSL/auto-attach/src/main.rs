@@ -1,15 +1,32 @@
 // SPDX-FileCopyrightText: 2022 Frans van Dorsselaer (original shell script)
 // SPDX-License-Identifier: GPL-3.0-only
 
-use std::env;
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::path::Path;
-use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use clap::Parser;
 
+mod control;
+mod glob;
+mod output;
+mod protocol;
+
+use output::Format;
+
+/// Settings shared by every watched device, including ones added later
+/// through the control socket.
+#[derive(Clone, Copy)]
+struct RunConfig {
+    verbose: bool,
+    format: Format,
+    max_backoff_seconds: u64,
+}
+
 /// A macro for conditional logging in verbose mode
 macro_rules! verbose_log {
     ($verbose:expr, $($arg:tt)*) => {
@@ -19,77 +36,231 @@ macro_rules! verbose_log {
     };
 }
 
+/// How a watched device is identified on its remote host.
+#[derive(Clone, Debug)]
+enum Filter {
+    /// A literal bus ID, or a `*`/`?` glob matched against the remote's
+    /// device list.
+    Busid(String),
+    /// Matches the first remote device with this USB vendor/product ID.
+    VidPid(u16, u16),
+}
+
+/// A single remote USB device to watch, as given via `--device HOST:BUSID`
+/// or `--vid-pid HOST:VID:PID`.
+#[derive(Clone, Debug)]
+struct Device {
+    host: String,
+    filter: Filter,
+}
+
+impl std::str::FromStr for Device {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((host, busid)) if !host.is_empty() && !busid.is_empty() => Ok(Device {
+                host: host.to_string(),
+                filter: Filter::Busid(busid.to_string()),
+            }),
+            _ => Err(format!("Invalid device '{}', expected HOST:BUSID", s)),
+        }
+    }
+}
+
+impl Device {
+    /// The canonical `HOST:BUSID` / `HOST:VID:PID` spelling of this device,
+    /// used both as its registry key and in control-socket commands.
+    fn spec(&self) -> String {
+        format!("{}:{}", self.host, self.filter.pattern())
+    }
+}
+
+impl Filter {
+    /// How this filter looks without its host, e.g. `1-*` or `1234:abcd`.
+    fn pattern(&self) -> String {
+        match self {
+            Filter::Busid(pattern) => pattern.clone(),
+            Filter::VidPid(vid, pid) => format!("{:04x}:{:04x}", vid, pid),
+        }
+    }
+}
+
+/// Parses a `--vid-pid HOST:VID:PID` argument into a `Device`.
+#[derive(Clone, Debug)]
+struct VidPidSpec(Device);
+
+impl std::str::FromStr for VidPidSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (host, vid, pid) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(host), Some(vid), Some(pid)) if !host.is_empty() => (host, vid, pid),
+            _ => return Err(format!("Invalid vid-pid device '{}', expected HOST:VID:PID", s)),
+        };
+
+        let vid = u16::from_str_radix(vid, 16).map_err(|_| format!("Invalid vendor ID '{}'", vid))?;
+        let pid = u16::from_str_radix(pid, 16).map_err(|_| format!("Invalid product ID '{}'", pid))?;
+
+        Ok(VidPidSpec(Device {
+            host: host.to_string(),
+            filter: Filter::VidPid(vid, pid),
+        }))
+    }
+}
+
+/// Resolves a device's filter against its remote host's current device
+/// list, returning the matching bus ID, if any.
+///
+/// A literal (non-glob) `Filter::Busid` resolves immediately without
+/// contacting the remote host, exactly as a directly-specified bus ID
+/// always has.
+fn resolve_busid(host: &str, filter: &Filter, verbose: bool) -> Result<Option<String>, String> {
+    if let Filter::Busid(pattern) = filter {
+        if !glob::is_glob(pattern) {
+            return Ok(Some(pattern.clone()));
+        }
+    }
+
+    let devices = protocol::list_devices(host)?;
+    let found = devices.into_iter().find(|d| match filter {
+        Filter::Busid(pattern) => glob::matches(pattern, &d.busid),
+        Filter::VidPid(vid, pid) => d.id_vendor == *vid && d.id_product == *pid,
+    });
+
+    match &found {
+        Some(d) => verbose_log!(verbose, "Filter matched remote busid {}", d.busid),
+        None => verbose_log!(verbose, "No remote device currently matches the filter"),
+    }
+
+    Ok(found.map(|d| d.busid))
+}
+
 struct AttachState {
     is_attached: bool,
     last_error: String,
     last_reported_error: String,
+    /// Consecutive failed attach attempts, used to back off against a host
+    /// that is down instead of retrying every `CHECK_INTERVAL_SECONDS`.
+    consecutive_failures: u32,
 }
 
-fn report_attached(state: &mut AttachState, attached: bool) {
+/// A device registered with the optional control socket: its live state,
+/// shared with any client querying `status`, and a flag a client can set to
+/// stop its monitor thread.
+struct Entry {
+    state: Arc<Mutex<AttachState>>,
+    stop: Arc<AtomicBool>,
+}
+
+/// Devices currently being watched, keyed by `Device::spec()`. Shared
+/// between the monitor threads and the control socket (if any).
+type Registry = Arc<Mutex<HashMap<String, Entry>>>;
+
+/// Spawns a thread that watches `device` forever (until its `stop` flag is
+/// set), registering it under `registry` so a control socket can inspect or
+/// remove it at runtime.
+fn spawn_device(device: Device, config: RunConfig, registry: &Registry) {
+    let state = Arc::new(Mutex::new(AttachState {
+        is_attached: false,
+        last_error: String::new(),
+        last_reported_error: String::new(),
+        consecutive_failures: 0,
+    }));
+    let stop = Arc::new(AtomicBool::new(false));
+
+    registry.lock().unwrap().insert(
+        device.spec(),
+        Entry {
+            state: Arc::clone(&state),
+            stop: Arc::clone(&stop),
+        },
+    );
+
+    thread::spawn(move || monitor_device(device, config, state, stop));
+}
+
+/// Updates `state` and, on a transition or a new error, emits a record via
+/// `output::report_transition`.
+fn report_attached(format: Format, host: &str, busid: &str, state: &mut AttachState, attached: bool) {
     let old_attached = state.is_attached;
     state.is_attached = attached;
 
     if state.is_attached != old_attached {
-        if state.is_attached {
-            println!("Attached");
-        } else {
-            println!("Detached");
-        }
+        output::report_transition(format, host, busid, state.is_attached);
         state.last_reported_error = String::new();
     }
 
     if !state.is_attached && state.last_reported_error != state.last_error {
-        println!("{}", state.last_error);
+        output::report_error(format, host, busid, state.is_attached, &state.last_error);
         state.last_reported_error = state.last_error.clone();
     }
 }
 
+/// Serializes vhci_hcd port selection and the attach write so concurrent
+/// monitor threads (chunk0-2) can't both pick the same free port and race
+/// to claim it.
+static VHCI_ATTACH_LOCK: Mutex<()> = Mutex::new(());
+
 fn try_attach(host: &str, busid: &str, verbose: bool) -> Result<String, String> {
-    // Using the current executable's directory to find usbip
-    let current_exe = match env::current_exe() {
-        Ok(path) => path,
-        Err(e) => {
-            verbose_log!(verbose, "Error finding current executable path: {}", e);
-            return Err(format!("Current executable error: {}", e));
-        }
+    verbose_log!(verbose, "Importing {} from {} over USB/IP", busid, host);
+    let (stream, device) = protocol::import_device(host, busid, verbose)?;
+    verbose_log!(
+        verbose,
+        "Imported device: busnum={} devnum={} speed={}",
+        device.busnum,
+        device.devnum,
+        device.speed
+    );
+
+    let _guard = VHCI_ATTACH_LOCK.lock().unwrap();
+
+    let port = match find_free_vhci_port(verbose) {
+        Ok(Some(port)) => port,
+        Ok(None) => return Err("No free vhci_hcd port available".to_string()),
+        Err(e) => return Err(format!("Failed to read vhci_hcd status: {}", e)),
     };
-    
-    let exe_dir = match current_exe.parent() {
-        Some(dir) => dir,
-        None => {
-            verbose_log!(verbose, "Could not determine executable directory from: {:?}", current_exe);
-            return Err("Could not determine executable directory".to_string());
+    verbose_log!(verbose, "Attaching to vhci_hcd port {}", port);
+
+    protocol::attach_to_vhci(&stream, port, &device)
+        .map_err(|e| format!("Failed to attach device to vhci_hcd: {}", e))?;
+
+    Ok(String::new())
+}
+
+/// Returns the first vhci_hcd port that currently has no device attached
+/// (`sockfd` is `0` in the status file), or `None` if all ports are in use.
+fn find_free_vhci_port(verbose: bool) -> io::Result<Option<u32>> {
+    let status_path = "/sys/devices/platform/vhci_hcd.0/status";
+    let status_content = fs::read_to_string(status_path)?;
+
+    let mut lines = status_content.lines();
+    lines.next(); // Skip header line
+
+    for line in lines {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 6 {
+            verbose_log!(verbose, "Skipping malformed status line: {}", line);
+            continue;
         }
-    };
-    
-    let usbip_path = exe_dir.join("usbip");
-    verbose_log!(verbose, "Looking for usbip at: {:?}", usbip_path);
-    
-    if !usbip_path.exists() {
-        verbose_log!(verbose, "usbip binary not found at: {:?}", usbip_path);
-        return Err(format!("usbip binary not found at: {:?}", usbip_path));
-    }
 
-    verbose_log!(verbose, "Executing: {:?} attach --remote {} --busid {}", usbip_path, host, busid);
-    let output = match Command::new(&usbip_path)
-        .args(&["attach", "--remote", host, "--busid", busid])
-        .output() {
-            Ok(output) => output,
+        let sockfd = match parts[5].parse::<i32>() {
+            Ok(val) => val,
             Err(e) => {
-                verbose_log!(verbose, "Failed to execute usbip command: {}", e);
-                return Err(format!("Command execution failed: {}", e));
+                verbose_log!(verbose, "Failed to parse sockfd '{}': {}", parts[5], e);
+                continue;
             }
         };
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-        verbose_log!(verbose, "usbip command failed with stderr: {}", stderr);
-        verbose_log!(verbose, "usbip command stdout: {}", stdout);
-        return Err(stderr);
+        if sockfd == 0 {
+            if let Ok(port) = parts[1].parse::<u32>() {
+                return Ok(Some(port));
+            }
+        }
     }
 
-    Ok(String::new())
+    Ok(None)
 }
 
 fn is_attached(host: &str, busid: &str, verbose: bool) -> io::Result<bool> {
@@ -208,20 +379,55 @@ fn safe_sleep(seconds: u64, verbose: bool) {
     thread::sleep(Duration::from_secs(seconds));
 }
 
-// Time between checks in seconds
+// Time between checks in seconds, and the starting point for backoff
 const CHECK_INTERVAL_SECONDS: u64 = 1;
 
+/// Exponential backoff after `consecutive_failures` failed attach attempts
+/// in a row: `CHECK_INTERVAL_SECONDS`, then doubling up to `max_seconds`.
+fn backoff_seconds(consecutive_failures: u32, max_seconds: u64) -> u64 {
+    if consecutive_failures == 0 {
+        return CHECK_INTERVAL_SECONDS;
+    }
+    let shift = (consecutive_failures - 1).min(63);
+    let doubled = CHECK_INTERVAL_SECONDS.saturating_mul(1u64 << shift);
+    doubled.min(max_seconds)
+}
+
 /// USB/IP auto-attach utility for Windows Subsystem for Linux
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Host IP address where the USB device is attached
-    #[clap(required = true)]
-    host: String,
+    /// Remote device to watch and auto-attach, as HOST:BUSID. BUSID may be
+    /// a `*`/`?` glob, which is re-matched against the remote's device list
+    /// on every check instead of requiring a fixed bus ID. May be given
+    /// more than once to watch several devices concurrently.
+    #[clap(long = "device", value_name = "HOST:BUSID")]
+    devices: Vec<Device>,
+
+    /// Remote device to watch and auto-attach by USB vendor/product ID
+    /// instead of bus ID, as HOST:VID:PID (hex). May be given more than
+    /// once.
+    #[clap(long = "vid-pid", value_name = "HOST:VID:PID")]
+    vid_pids: Vec<VidPidSpec>,
+
+    /// Query HOST for the devices it currently exports and print them,
+    /// instead of watching anything.
+    #[clap(long, value_name = "HOST")]
+    list: Option<String>,
+
+    /// Bind a Unix domain socket at PATH serving a control/status protocol
+    /// for this running daemon (see `control` module for the commands).
+    #[clap(long, value_name = "PATH")]
+    control_socket: Option<String>,
 
-    /// Bus ID of the USB device to attach
-    #[clap(required = true)]
-    busid: String,
+    /// Output format for state-transition records.
+    #[clap(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Cap, in seconds, on the exponential backoff applied after
+    /// consecutive failed attach attempts.
+    #[clap(long, value_name = "SECONDS", default_value_t = 60)]
+    max_backoff_seconds: u64,
 
     /// Enable verbose logging
     #[clap(short, long)]
@@ -231,52 +437,157 @@ struct Args {
 fn main() {
     // Parse command line arguments using clap
     let args = Args::parse();
-    
-    let verbose = args.verbose;
-    let host = &args.host;
-    let busid = &args.busid;
-    
-    verbose_log!(verbose, "Starting auto-attach with host={}, busid={}", host, busid);
-    
-    let mut state = AttachState {
-        is_attached: false,
-        last_error: String::new(),
-        last_reported_error: String::new(),
+
+    if let Some(host) = &args.list {
+        list_remote_devices(host);
+        return;
+    }
+
+    let mut devices = args.devices;
+    devices.extend(args.vid_pids.into_iter().map(|spec| spec.0));
+
+    if devices.is_empty() {
+        eprintln!("Specify at least one --device, --vid-pid, or --list");
+        std::process::exit(2);
+    }
+
+    let config = RunConfig {
+        verbose: args.verbose,
+        format: args.format,
+        max_backoff_seconds: args.max_backoff_seconds,
     };
 
+    let registry: Registry = Arc::new(Mutex::new(HashMap::new()));
+    for device in devices {
+        spawn_device(device, config, &registry);
+    }
+
+    if let Some(path) = &args.control_socket {
+        if let Err(e) = control::serve(path, Arc::clone(&registry), config) {
+            eprintln!("Failed to serve control socket at {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+
+    // Without a control socket nothing can ever stop a monitor thread, so
+    // parking here simply keeps the process alive.
     loop {
-        verbose_log!(verbose, "Checking if device is attached...");
-        match is_attached(host, busid, verbose) {
-            Ok(true) => {
-                verbose_log!(verbose, "Device is attached");
-                report_attached(&mut state, true);
-            },
-            Ok(false) => {
-                verbose_log!(verbose, "Device is not attached");
-                report_attached(&mut state, false);
-                
-                // Always try to attach when the device is not found, like in the bash script
-                verbose_log!(verbose, "Attempting to attach device");
-                match try_attach(host, busid, verbose) {
-                    Ok(_) => {
-                        verbose_log!(verbose, "Attachment successful");
-                        state.last_error = String::new();
-                        report_attached(&mut state, true);
-                    },
-                    Err(error) => {
-                        verbose_log!(verbose, "Attachment failed: {}", error);
-                        state.last_error = error;
-                        report_attached(&mut state, false);
-                    }
+        thread::park();
+    }
+}
+
+/// Queries `host` for its exportable devices and prints them to stdout.
+fn list_remote_devices(host: &str) {
+    match protocol::list_devices(host) {
+        Ok(devices) if devices.is_empty() => println!("{} exports no devices", host),
+        Ok(devices) => {
+            for device in devices {
+                println!(
+                    "{}:{}  {:04x}:{:04x}  busnum={} devnum={} speed={}",
+                    host, device.busid, device.id_vendor, device.id_product, device.busnum, device.devnum, device.speed
+                );
+            }
+        },
+        Err(e) => {
+            eprintln!("Failed to list devices on {}: {}", host, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Polls a single remote device until `stop` is set, auto-attaching it
+/// whenever it is found to be detached. Consecutive failed attach attempts
+/// back off exponentially, capped at `config.max_backoff_seconds`.
+fn monitor_device(device: Device, config: RunConfig, state: Arc<Mutex<AttachState>>, stop: Arc<AtomicBool>) {
+    let verbose = config.verbose;
+    let host = device.host.as_str();
+    let tag = format!("[{}]", device.spec());
+
+    verbose_log!(verbose, "{} Starting auto-attach", tag);
+
+    while !stop.load(Ordering::Relaxed) {
+        let healthy = check_device(&tag, host, &device.filter, config, &state);
+
+        let sleep_seconds = {
+            let mut state = state.lock().unwrap();
+            state.consecutive_failures = if healthy { 0 } else { state.consecutive_failures + 1 };
+            backoff_seconds(state.consecutive_failures, config.max_backoff_seconds)
+        };
+
+        safe_sleep(sleep_seconds, verbose);
+    }
+
+    verbose_log!(verbose, "{} Stopped (removed via control socket)", tag);
+}
+
+/// Resolves `filter` and, if it currently matches a remote device, checks
+/// and maintains its attach state. `state` is locked only to snapshot its
+/// fields and to apply/report a result — never across the network I/O in
+/// `resolve_busid`/`check_and_attach`, so a `status` control command never
+/// blocks on an unreachable host. Returns `false` if this tick ended with a
+/// failed resolve, status check or attach attempt, so the caller can back off.
+fn check_device(tag: &str, host: &str, filter: &Filter, config: RunConfig, state: &Arc<Mutex<AttachState>>) -> bool {
+    let verbose = config.verbose;
+    match resolve_busid(host, filter, verbose) {
+        Ok(Some(busid)) => check_and_attach(tag, host, &busid, config, state),
+        Ok(None) => {
+            verbose_log!(verbose, "{} No matching remote device found", tag);
+            report_attached(config.format, host, &filter.pattern(), &mut state.lock().unwrap(), false);
+            true
+        },
+        Err(e) => {
+            verbose_log!(verbose, "{} Error resolving device filter: {}", tag, e);
+            let mut state = state.lock().unwrap();
+            state.last_error = e;
+            report_attached(config.format, host, &filter.pattern(), &mut state, false);
+            false
+        }
+    }
+}
+
+/// Checks whether `busid` is currently attached and, if not, attempts to
+/// attach it, reporting any state transition. `state` is locked only around
+/// each report, not across the I/O in `is_attached`/`try_attach`.
+/// Returns `false` if this tick ended with a failed status check or attach
+/// attempt, so the caller can back off.
+fn check_and_attach(tag: &str, host: &str, busid: &str, config: RunConfig, state: &Arc<Mutex<AttachState>>) -> bool {
+    let verbose = config.verbose;
+    verbose_log!(verbose, "{} Checking if device is attached...", tag);
+    match is_attached(host, busid, verbose) {
+        Ok(true) => {
+            verbose_log!(verbose, "{} Device is attached", tag);
+            report_attached(config.format, host, busid, &mut state.lock().unwrap(), true);
+            true
+        },
+        Ok(false) => {
+            verbose_log!(verbose, "{} Device is not attached", tag);
+            report_attached(config.format, host, busid, &mut state.lock().unwrap(), false);
+
+            // Always try to attach when the device is not found, like in the bash script
+            verbose_log!(verbose, "{} Attempting to attach device", tag);
+            match try_attach(host, busid, verbose) {
+                Ok(_) => {
+                    verbose_log!(verbose, "{} Attachment successful", tag);
+                    let mut state = state.lock().unwrap();
+                    state.last_error = String::new();
+                    report_attached(config.format, host, busid, &mut state, true);
+                    true
+                },
+                Err(error) => {
+                    verbose_log!(verbose, "{} Attachment failed: {}", tag, error);
+                    let mut state = state.lock().unwrap();
+                    state.last_error = error;
+                    report_attached(config.format, host, busid, &mut state, false);
+                    false
                 }
-            },
-            Err(e) => {
-                verbose_log!(verbose, "Error checking attachment status: {}", e);
-                state.last_error = e.to_string();
-                report_attached(&mut state, false);
             }
+        },
+        Err(e) => {
+            verbose_log!(verbose, "{} Error checking attachment status: {}", tag, e);
+            let mut state = state.lock().unwrap();
+            state.last_error = e.to_string();
+            report_attached(config.format, host, busid, &mut state, false);
+            false
         }
-        
-        safe_sleep(CHECK_INTERVAL_SECONDS, verbose);
     }
 }
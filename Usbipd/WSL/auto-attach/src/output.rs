@@ -0,0 +1,79 @@
+// SPDX-FileCopyrightText: 2022 Frans van Dorsselaer (original shell script)
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! State-transition reporting, either as free-text lines for a human or as
+//! single-line JSON records for a supervising process.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Format {
+    /// Free-text `Attached`/`Detached`/error lines, each prefixed with
+    /// `[HOST:BUSID]`.
+    Text,
+    /// One JSON object per line: `{"timestamp","host","busid","state","error"}`.
+    Json,
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Format::Text => "text",
+            Format::Json => "json",
+        })
+    }
+}
+
+/// Prints an `Attached`/`Detached` state-transition record for `host`/`busid`.
+pub fn report_transition(format: Format, host: &str, busid: &str, attached: bool) {
+    report(format, host, busid, attached, "");
+}
+
+/// Prints a record for a new error encountered while `host`/`busid` is in
+/// the given attach state.
+pub fn report_error(format: Format, host: &str, busid: &str, attached: bool, error: &str) {
+    report(format, host, busid, attached, error);
+}
+
+fn report(format: Format, host: &str, busid: &str, attached: bool, error: &str) {
+    let state = if attached { "Attached" } else { "Detached" };
+    match format {
+        Format::Text => {
+            if error.is_empty() {
+                println!("[{}:{}] {}", host, busid, state);
+            } else {
+                println!("[{}:{}] {}", host, busid, error);
+            }
+        },
+        Format::Json => {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            println!(
+                "{{\"timestamp\":{},\"host\":{},\"busid\":{},\"state\":{},\"error\":{}}}",
+                timestamp,
+                json_string(host),
+                json_string(busid),
+                json_string(state),
+                json_string(error)
+            );
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
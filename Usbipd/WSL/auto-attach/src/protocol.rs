@@ -0,0 +1,251 @@
+// SPDX-FileCopyrightText: 2022 Frans van Dorsselaer (original shell script)
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Minimal client-side implementation of the USB/IP wire protocol: importing
+//! ("attaching") a remote device and listing a remote host's exportable
+//! devices. See the Linux USB/IP userspace tools (`usbip_network.h`,
+//! `usbip_common.h`) for the canonical definitions of the packets and
+//! structures implemented here.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+pub const USBIP_PORT: u16 = 3240;
+
+const USBIP_VERSION: u16 = 0x0111;
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+const OP_REQ_DEVLIST: u16 = 0x8005;
+const OP_REP_DEVLIST: u16 = 0x0005;
+
+/// Cap on the time spent connecting to a remote host, so an unreachable
+/// (as opposed to merely refusing) host can't block a poll tick for the OS
+/// default connect timeout.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves `host:3240` and connects with a bounded timeout, trying each
+/// resolved address in turn.
+fn connect(host: &str) -> Result<TcpStream, String> {
+    let addrs: Vec<SocketAddr> = (host, USBIP_PORT)
+        .to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve {}:{}: {}", host, USBIP_PORT, e))?
+        .collect();
+
+    let mut last_err = None;
+    for addr in &addrs {
+        match TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(format!(
+        "Failed to connect to {}:{}: {}",
+        host,
+        USBIP_PORT,
+        last_err.map(|e| e.to_string()).unwrap_or_else(|| "no addresses found".to_string())
+    ))
+}
+
+const SYSFS_BUS_ID_SIZE: usize = 32;
+const SYSFS_PATH_MAX: usize = 256;
+
+/// Size in bytes of the wire-format `usbip_usb_device` struct: path[256],
+/// busid[32], busnum, devnum, speed (u32 each), idVendor, idProduct,
+/// bcdDevice (u16 each), then six class/configuration bytes.
+const USBIP_USB_DEVICE_SIZE: usize =
+    SYSFS_PATH_MAX + SYSFS_BUS_ID_SIZE + 4 + 4 + 4 + 2 + 2 + 2 + 1 + 1 + 1 + 1 + 1 + 1;
+
+/// Size in bytes of one `usbip_usb_interface` entry that follows a device's
+/// `usbip_usb_device` struct in an `OP_REP_DEVLIST` reply.
+const USBIP_USB_INTERFACE_SIZE: usize = 4;
+
+/// The subset of `usbip_usb_device` fields needed to attach a device to the
+/// local vhci-hcd.
+pub struct ImportedDevice {
+    pub busnum: u32,
+    pub devnum: u32,
+    pub speed: u32,
+}
+
+/// A device advertised by a remote host's `OP_REP_DEVLIST` reply.
+pub struct RemoteDevice {
+    pub busid: String,
+    pub busnum: u32,
+    pub devnum: u32,
+    pub speed: u32,
+    pub id_vendor: u16,
+    pub id_product: u16,
+}
+
+fn encode_busid(busid: &str) -> Result<[u8; SYSFS_BUS_ID_SIZE], String> {
+    let bytes = busid.as_bytes();
+    if bytes.len() >= SYSFS_BUS_ID_SIZE {
+        return Err(format!("Bus ID too long: {}", busid));
+    }
+    let mut field = [0u8; SYSFS_BUS_ID_SIZE];
+    field[..bytes.len()].copy_from_slice(bytes);
+    Ok(field)
+}
+
+/// Connects to `host:3240` and sends an `OP_REQ_IMPORT` for `busid`.
+///
+/// On success, returns the still-open `TcpStream` together with the device
+/// information needed to attach it locally. The caller must hand the
+/// stream's file descriptor to the kernel (via the vhci-hcd `attach` sysfs
+/// file) before dropping it, as the kernel expects to take over the
+/// connection at that point.
+pub fn import_device(host: &str, busid: &str, verbose: bool) -> Result<(TcpStream, ImportedDevice), String> {
+    let stream = connect(host)?;
+    verbose_log_connected(verbose, host);
+
+    send_import_request(&stream, busid)?;
+    let device = read_import_reply(&stream, host, busid)?;
+
+    Ok((stream, device))
+}
+
+fn verbose_log_connected(verbose: bool, host: &str) {
+    if verbose {
+        eprintln!("Connected to {}:{}", host, USBIP_PORT);
+    }
+}
+
+fn send_import_request(mut stream: &TcpStream, busid: &str) -> Result<(), String> {
+    let busid_field = encode_busid(busid)?;
+
+    let mut request = Vec::with_capacity(8 + SYSFS_BUS_ID_SIZE);
+    request.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+    request.extend_from_slice(&OP_REQ_IMPORT.to_be_bytes());
+    request.extend_from_slice(&0u32.to_be_bytes());
+    request.extend_from_slice(&busid_field);
+
+    stream
+        .write_all(&request)
+        .map_err(|e| format!("Failed to send OP_REQ_IMPORT: {}", e))
+}
+
+fn read_import_reply(mut stream: &TcpStream, host: &str, busid: &str) -> Result<ImportedDevice, String> {
+    let mut header = [0u8; 8];
+    stream
+        .read_exact(&mut header)
+        .map_err(|e| format!("Failed to read OP_REP_IMPORT header: {}", e))?;
+
+    let version = u16::from_be_bytes([header[0], header[1]]);
+    let code = u16::from_be_bytes([header[2], header[3]]);
+    let status = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+
+    if version != USBIP_VERSION || code != OP_REP_IMPORT {
+        return Err(format!(
+            "Unexpected reply from {}: version={:#06x} code={:#06x}",
+            host, version, code
+        ));
+    }
+
+    if status != 0 {
+        return Err(format!("{} refused to import busid {}: status={}", host, busid, status));
+    }
+
+    let mut device = [0u8; USBIP_USB_DEVICE_SIZE];
+    stream
+        .read_exact(&mut device)
+        .map_err(|e| format!("Failed to read usbip_usb_device: {}", e))?;
+
+    let remote = parse_usbip_usb_device(&device);
+    Ok(ImportedDevice {
+        busnum: remote.busnum,
+        devnum: remote.devnum,
+        speed: remote.speed,
+    })
+}
+
+/// Parses a wire-format `usbip_usb_device` struct.
+fn parse_usbip_usb_device(device: &[u8; USBIP_USB_DEVICE_SIZE]) -> RemoteDevice {
+    let busid_field = &device[SYSFS_PATH_MAX..SYSFS_PATH_MAX + SYSFS_BUS_ID_SIZE];
+    let busid_len = busid_field.iter().position(|&b| b == 0).unwrap_or(busid_field.len());
+    let busid = String::from_utf8_lossy(&busid_field[..busid_len]).into_owned();
+
+    let offset = SYSFS_PATH_MAX + SYSFS_BUS_ID_SIZE;
+    let busnum = u32::from_be_bytes(device[offset..offset + 4].try_into().unwrap());
+    let devnum = u32::from_be_bytes(device[offset + 4..offset + 8].try_into().unwrap());
+    let speed = u32::from_be_bytes(device[offset + 8..offset + 12].try_into().unwrap());
+    let id_vendor = u16::from_be_bytes(device[offset + 12..offset + 14].try_into().unwrap());
+    let id_product = u16::from_be_bytes(device[offset + 14..offset + 16].try_into().unwrap());
+
+    RemoteDevice {
+        busid,
+        busnum,
+        devnum,
+        speed,
+        id_vendor,
+        id_product,
+    }
+}
+
+/// Queries `host:3240` for the list of devices it currently exports.
+pub fn list_devices(host: &str) -> Result<Vec<RemoteDevice>, String> {
+    let mut stream = connect(host)?;
+
+    let mut request = Vec::with_capacity(8);
+    request.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+    request.extend_from_slice(&OP_REQ_DEVLIST.to_be_bytes());
+    request.extend_from_slice(&0u32.to_be_bytes());
+    stream
+        .write_all(&request)
+        .map_err(|e| format!("Failed to send OP_REQ_DEVLIST: {}", e))?;
+
+    let mut header = [0u8; 8];
+    stream
+        .read_exact(&mut header)
+        .map_err(|e| format!("Failed to read OP_REP_DEVLIST header: {}", e))?;
+
+    let version = u16::from_be_bytes([header[0], header[1]]);
+    let code = u16::from_be_bytes([header[2], header[3]]);
+    let status = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+
+    if version != USBIP_VERSION || code != OP_REP_DEVLIST {
+        return Err(format!(
+            "Unexpected reply from {}: version={:#06x} code={:#06x}",
+            host, version, code
+        ));
+    }
+
+    if status != 0 {
+        return Err(format!("{} refused OP_REQ_DEVLIST: status={}", host, status));
+    }
+
+    let mut count_bytes = [0u8; 4];
+    stream
+        .read_exact(&mut count_bytes)
+        .map_err(|e| format!("Failed to read device count: {}", e))?;
+    let count = u32::from_be_bytes(count_bytes);
+
+    let mut devices = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut device = [0u8; USBIP_USB_DEVICE_SIZE];
+        stream
+            .read_exact(&mut device)
+            .map_err(|e| format!("Failed to read usbip_usb_device: {}", e))?;
+        let remote = parse_usbip_usb_device(&device);
+
+        let num_interfaces = device[USBIP_USB_DEVICE_SIZE - 1] as usize;
+        let mut interfaces = vec![0u8; num_interfaces * USBIP_USB_INTERFACE_SIZE];
+        stream
+            .read_exact(&mut interfaces)
+            .map_err(|e| format!("Failed to read usbip_usb_interface entries: {}", e))?;
+
+        devices.push(remote);
+    }
+
+    Ok(devices)
+}
+
+/// Writes the imported device's socket to the vhci-hcd `attach` sysfs file,
+/// handing ownership of the connection over to the kernel.
+pub fn attach_to_vhci(stream: &TcpStream, port: u32, device: &ImportedDevice) -> io::Result<()> {
+    let devid = (device.busnum << 16) | device.devnum;
+    let line = format!("{} {} {} {}\n", port, stream.as_raw_fd(), devid, device.speed);
+    std::fs::write("/sys/devices/platform/vhci_hcd.0/attach", line)
+}